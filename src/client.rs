@@ -1,20 +1,46 @@
 use proto;
 
-use byteorder::{BigEndian, WriteBytesExt};
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
 
 use std;
+use std::collections::VecDeque;
+use std::error;
+use std::fmt;
+use std::io::Cursor;
 use std::net::{IpAddr, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::sync::mpsc;
 use std::sync::{Arc, Mutex};
 use std::{thread, time};
 
+use mio::unix::EventedFd;
+use mio::{Events, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
+
 use openssl;
-use openssl::ssl::{HandshakeError, SslContext, SslMethod, SslStream};
+use openssl::pkcs12::Pkcs12;
+use openssl::ssl::{HandshakeError, Ssl, SslContext, SslMethod, SslSession, SslStream, SSL_VERIFY_NONE, SSL_VERIFY_PEER};
+use openssl::x509::X509FileType;
 
 use protobuf;
 
 // Connect
 const SSL_HANDSHAKE_RETRIES: u8 = 3;
 
+// Session resumption
+const SESSION_CACHE_SIZE: usize = 4;
+
+// Event loop
+const CONTROL_CHANNEL_TOKEN: Token = Token(0);
+const WAKE_TOKEN: Token = Token(1);
+
+// Receive
+const FRAME_HEADER_SIZE: usize = 6;
+const RECV_CHUNK_SIZE: usize = 4096;
+// Mirrors the payload size murmur itself refuses to exceed; callers can lower this
+// via `ClientConfig::max_frame_payload_size` but not raise it past what the wire format
+// (a 4-byte length) allows.
+const DEFAULT_MAX_FRAME_PAYLOAD_SIZE: u32 = 10 * 1024 * 1024;
+
 // Version Exchange
 const VERSION_RELEASE_PREFIX: &'static str = "mumble-rs";
 const VERSION_RELEASE: Option<&'static str> = option_env!("CARGO_PKG_VERSION");
@@ -29,8 +55,37 @@ const PING_INTERVAL: u64 = 5; // (in seconds)
 #[derive(Debug)]
 pub enum Error {
     ConnectionError(ConnectionError),
-    SendError(SendError)
-} // TODO: this should impl error, display
+    SendError(SendError),
+    DecodeError(DecodeError)
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::ConnectionError(ref err) => write!(f, "{}", err),
+            Error::SendError(ref err) => write!(f, "{}", err),
+            Error::DecodeError(ref err) => write!(f, "{}", err)
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::ConnectionError(ref err) => err.description(),
+            Error::SendError(ref err) => err.description(),
+            Error::DecodeError(ref err) => err.description()
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            Error::ConnectionError(ref err) => Some(err),
+            Error::SendError(ref err) => Some(err),
+            Error::DecodeError(ref err) => Some(err)
+        }
+    }
+}
 
 impl From<ConnectionError> for Error {
     fn from(e: ConnectionError) -> Self {
@@ -44,29 +99,767 @@ impl From<SendError> for Error {
     }
 }
 
+impl From<DecodeError> for Error {
+    fn from(e: DecodeError) -> Self {
+        Error::DecodeError(e)
+    }
+}
+
+impl From<openssl::ssl::Error> for Error {
+    fn from(e: openssl::ssl::Error) -> Self {
+        Error::ConnectionError(ConnectionError::from(e))
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::ConnectionError(ConnectionError::from(e))
+    }
+}
+
+impl From<protobuf::ProtobufError> for Error {
+    fn from(e: protobuf::ProtobufError) -> Self {
+        Error::SendError(SendError::from(e))
+    }
+}
+
 #[derive(Debug)]
 pub enum ConnectionError {
     ExceededHandshakeRetries(&'static str),
     Ssl(openssl::ssl::Error),
-    TcpStream(std::io::Error)
-} // TODO: this should impl error, display, from
+    TcpStream(std::io::Error),
+    CertVerification(openssl::ssl::Error),
+    ClientCert(std::io::Error)
+}
+
+impl fmt::Display for ConnectionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConnectionError::ExceededHandshakeRetries(msg) => write!(f, "{}", msg),
+            ConnectionError::Ssl(ref err) => write!(f, "TLS error: {}", err),
+            ConnectionError::TcpStream(ref err) => write!(f, "failed to connect: {}", err),
+            ConnectionError::CertVerification(ref err) => write!(f, "server certificate verification failed: {}", err),
+            ConnectionError::ClientCert(ref err) => write!(f, "failed to load client certificate: {}", err)
+        }
+    }
+}
+
+impl error::Error for ConnectionError {
+    fn description(&self) -> &str {
+        match *self {
+            ConnectionError::ExceededHandshakeRetries(msg) => msg,
+            ConnectionError::Ssl(_) => "TLS error",
+            ConnectionError::TcpStream(_) => "failed to connect",
+            ConnectionError::CertVerification(_) => "server certificate verification failed",
+            ConnectionError::ClientCert(_) => "failed to load client certificate"
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            ConnectionError::Ssl(ref err) => Some(err),
+            ConnectionError::TcpStream(ref err) => Some(err),
+            ConnectionError::CertVerification(ref err) => Some(err),
+            ConnectionError::ClientCert(ref err) => Some(err),
+            ConnectionError::ExceededHandshakeRetries(_) => None
+        }
+    }
+}
+
+impl From<std::io::Error> for ConnectionError {
+    fn from(e: std::io::Error) -> Self {
+        ConnectionError::TcpStream(e)
+    }
+}
+
+impl From<openssl::ssl::Error> for ConnectionError {
+    fn from(e: openssl::ssl::Error) -> Self {
+        ConnectionError::Ssl(e)
+    }
+}
+
+/// Controls how the server's TLS certificate is (or isn't) validated during the handshake.
+///
+/// Mumble servers don't typically present certificates signed by a public CA, so
+/// `VerifyMode::None` remains the default, but callers that know what they're talking to
+/// should prefer `SystemRoots` or `PinnedCert`.
+pub enum VerifyMode {
+    /// Accept any certificate the server presents. Insecure; this is the historical default.
+    None,
+    /// Verify the server's certificate against the system's trusted root store.
+    ///
+    /// This checks the certificate *chain* only - it does not verify that the leaf
+    /// certificate's CN/SAN matches the `host` a connection is made to, since this version
+    /// of the underlying `openssl` bindings has no hostname-checking verify param. A
+    /// certificate that chains to a trusted root but was issued for an unrelated name is
+    /// still accepted, which defeats the point of this mode against an active MITM. Prefer
+    /// `PinnedCert` unless the server's identity is also checked some other way (e.g.
+    /// Mumble's own certificate-fingerprint-based user authentication).
+    SystemRoots,
+    /// Trust the given certificate as the sole root for this connection, ignoring the
+    /// system root store. This is chain-based trust, not leaf pinning: OpenSSL will accept
+    /// any certificate that chains to it, not only this exact certificate. That's fine when
+    /// the given certificate is a self-signed Mumble server cert known in advance (the
+    /// common case this exists for), since nothing else can chain to it, but it is not a
+    /// guarantee that the server's certificate is byte-for-byte this one.
+    PinnedCert(std::path::PathBuf)
+}
+
+/// A client certificate and private key to present during the TLS handshake.
+///
+/// Mumble servers identify and auto-register users by the certificate fingerprint, so for
+/// most deployments this is the real authentication mechanism, not the username/password
+/// exchanged afterwards.
+pub enum ClientCert {
+    /// A certificate and private key stored as separate PEM files.
+    Pem { cert_file: std::path::PathBuf, key_file: std::path::PathBuf },
+    /// A certificate and private key bundled together in a single PKCS#12 file, the format
+    /// Mumble's own clients commonly export.
+    Pkcs12 { file: std::path::PathBuf, password: String }
+}
+
+/// Configuration for a `Client` connection, covering everything that used to be hard-coded
+/// constants in `Client::connect`.
+pub struct ClientConfig {
+    verify_mode: VerifyMode,
+    client_cert: Option<ClientCert>,
+    max_frame_payload_size: u32
+}
+
+impl ClientConfig {
+    pub fn new() -> ClientConfig {
+        ClientConfig {
+            verify_mode: VerifyMode::None,
+            client_cert: None,
+            max_frame_payload_size: DEFAULT_MAX_FRAME_PAYLOAD_SIZE
+        }
+    }
+
+    pub fn verify_mode(mut self, mode: VerifyMode) -> ClientConfig {
+        self.verify_mode = mode;
+        self
+    }
+
+    pub fn client_cert(mut self, cert: ClientCert) -> ClientConfig {
+        self.client_cert = Some(cert);
+        self
+    }
+
+    /// Caps how large a single control-channel message's payload is allowed to declare
+    /// itself to be. Frames over this are reported as `DecodeError::PayloadTooLarge`
+    /// instead of being buffered.
+    pub fn max_frame_payload_size(mut self, max: u32) -> ClientConfig {
+        self.max_frame_payload_size = max;
+        self
+    }
+}
+
+impl Default for ClientConfig {
+    fn default() -> ClientConfig {
+        ClientConfig::new()
+    }
+}
+
+/// Whether the most recent handshake negotiated a brand-new TLS session or resumed one
+/// cached from an earlier connection to the same `Client`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeState {
+    Full,
+    Resumed
+}
+
+/// Caches the most recently negotiated TLS sessions so a later `reconnect` can offer one
+/// and let the server perform an abbreviated handshake instead of a full negotiation.
+/// Bounded to `SESSION_CACHE_SIZE` entries; the oldest is evicted to make room for a new
+/// one, and a session the server refuses to resume is evicted immediately.
+struct SessionCache {
+    sessions: VecDeque<SslSession>
+}
+
+impl SessionCache {
+    fn new() -> SessionCache {
+        SessionCache { sessions: VecDeque::new() }
+    }
+
+    fn push(&mut self, session: SslSession) {
+        if self.sessions.len() >= SESSION_CACHE_SIZE {
+            self.sessions.pop_front();
+        }
+        self.sessions.push_back(session);
+    }
+
+    fn most_recent(&self) -> Option<&SslSession> {
+        self.sessions.back()
+    }
+
+    fn evict_most_recent(&mut self) {
+        self.sessions.pop_back();
+    }
+}
+
+struct TlsState {
+    sessions: SessionCache,
+    last_handshake: HandshakeState
+}
 
 #[derive(Debug)]
 pub enum SendError {
     MessageTooLarge(&'static str),
-    Ssl(openssl::ssl::Error)
-} // TODO: this should impl error, display, from
+    Encode(protobuf::ProtobufError),
+    Ssl(openssl::ssl::Error),
+    EventLoopGone
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SendError::MessageTooLarge(msg) => write!(f, "{}", msg),
+            SendError::Encode(ref err) => write!(f, "failed to encode outgoing message: {}", err),
+            SendError::Ssl(ref err) => write!(f, "failed to write to the control channel: {}", err),
+            SendError::EventLoopGone => write!(f, "the connection's event loop has already shut down")
+        }
+    }
+}
+
+impl error::Error for SendError {
+    fn description(&self) -> &str {
+        match *self {
+            SendError::MessageTooLarge(msg) => msg,
+            SendError::Encode(_) => "failed to encode outgoing message",
+            SendError::Ssl(_) => "failed to write to the control channel",
+            SendError::EventLoopGone => "the connection's event loop has already shut down"
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            SendError::Encode(ref err) => Some(err),
+            SendError::Ssl(ref err) => Some(err),
+            SendError::MessageTooLarge(_) | SendError::EventLoopGone => None
+        }
+    }
+}
+
+impl From<protobuf::ProtobufError> for SendError {
+    fn from(e: protobuf::ProtobufError) -> Self {
+        SendError::Encode(e)
+    }
+}
+
+/// A message decoded off the server's control channel.
+///
+/// Variants mirror the message IDs the Mumble protocol sends on the TCP control channel.
+#[derive(Debug)]
+pub enum ServerMessage {
+    Version(proto::Version),
+    UDPTunnel(Vec<u8>),
+    Authenticate(proto::Authenticate),
+    Ping(proto::Ping),
+    Reject(proto::Reject),
+    ServerSync(proto::ServerSync),
+    ChannelRemove(proto::ChannelRemove),
+    ChannelState(proto::ChannelState),
+    UserRemove(proto::UserRemove),
+    UserState(proto::UserState),
+    BanList(proto::BanList),
+    TextMessage(proto::TextMessage),
+    PermissionDenied(proto::PermissionDenied),
+    ACL(proto::ACL),
+    QueryUsers(proto::QueryUsers),
+    CryptSetup(proto::CryptSetup),
+    ContextActionModify(proto::ContextActionModify),
+    ContextAction(proto::ContextAction),
+    UserList(proto::UserList),
+    VoiceTarget(proto::VoiceTarget),
+    PermissionQuery(proto::PermissionQuery),
+    CodecVersion(proto::CodecVersion),
+    UserStats(proto::UserStats),
+    RequestBlob(proto::RequestBlob),
+    ServerConfig(proto::ServerConfig),
+    SuggestConfig(proto::SuggestConfig)
+}
+
+/// A malformed or unrecognized frame on the incoming control channel.
+///
+/// Each case carries the offending message ID (and length, where relevant) rather than
+/// collapsing into one opaque "corrupt message" error, so callers can log and recover
+/// meaningfully instead of just tearing down the connection blind.
+#[derive(Debug)]
+pub enum DecodeError {
+    UnknownMessageType(u16),
+    PayloadTooLarge { id: u16, len: u32, max: u32 },
+    TruncatedPayload { id: u16, expected: u32, got: usize },
+    Protobuf { id: u16, source: protobuf::ProtobufError }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecodeError::UnknownMessageType(id) => write!(f, "unknown message type {}", id),
+            DecodeError::PayloadTooLarge { id, len, max } => {
+                write!(f, "message {} declared a {} byte payload, exceeding the {} byte cap", id, len, max)
+            }
+            DecodeError::TruncatedPayload { id, expected, got } => {
+                write!(f, "message {} was truncated: expected {} bytes of payload, got {}", id, expected, got)
+            }
+            DecodeError::Protobuf { id, ref source } => write!(f, "failed to parse message {}: {}", id, source)
+        }
+    }
+}
+
+impl error::Error for DecodeError {
+    fn description(&self) -> &str {
+        match *self {
+            DecodeError::UnknownMessageType(_) => "unknown message type",
+            DecodeError::PayloadTooLarge { .. } => "message payload exceeded the configured cap",
+            DecodeError::TruncatedPayload { .. } => "message payload was truncated",
+            DecodeError::Protobuf { .. } => "failed to parse message payload"
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            DecodeError::Protobuf { ref source, .. } => Some(source),
+            _ => None
+        }
+    }
+}
+
+fn decode_message(id: u16, payload: &[u8]) -> Result<ServerMessage, DecodeError> {
+    macro_rules! parse {
+        ($variant:ident) => {
+            match protobuf::core::parse_from_bytes(payload) {
+                Ok(val) => ServerMessage::$variant(val),
+                Err(err) => return Err(DecodeError::Protobuf { id: id, source: err })
+            }
+        }
+    }
+    Ok(match id {
+        0 => parse!(Version),
+        1 => ServerMessage::UDPTunnel(payload.to_vec()),
+        2 => parse!(Authenticate),
+        3 => parse!(Ping),
+        4 => parse!(Reject),
+        5 => parse!(ServerSync),
+        6 => parse!(ChannelRemove),
+        7 => parse!(ChannelState),
+        8 => parse!(UserRemove),
+        9 => parse!(UserState),
+        10 => parse!(BanList),
+        11 => parse!(TextMessage),
+        12 => parse!(PermissionDenied),
+        13 => parse!(ACL),
+        14 => parse!(QueryUsers),
+        15 => parse!(CryptSetup),
+        16 => parse!(ContextActionModify),
+        17 => parse!(ContextAction),
+        18 => parse!(UserList),
+        19 => parse!(VoiceTarget),
+        20 => parse!(PermissionQuery),
+        21 => parse!(CodecVersion),
+        22 => parse!(UserStats),
+        23 => parse!(RequestBlob),
+        24 => parse!(ServerConfig),
+        25 => parse!(SuggestConfig),
+        other => return Err(DecodeError::UnknownMessageType(other))
+    })
+}
+
+/// Reassembles length-prefixed frames (2-byte big-endian message ID, 4-byte big-endian
+/// payload length, then the payload) out of however many bytes the transport hands back
+/// at a time.
+///
+/// `feed` appends newly-read bytes; `try_take` pulls a complete frame out once enough
+/// bytes have accumulated, leaving any leftover bytes buffered for the next frame. This
+/// keeps framing correct whether a read returns a partial header, a partial payload, or
+/// several whole frames at once. A declared payload length over `max_payload_size` is
+/// reported instead of buffered, so a corrupt or hostile length prefix can't be used to
+/// force unbounded allocation.
+struct FramedReader {
+    rec_buf: Vec<u8>,
+    expect: usize,
+    message_id: Option<u16>,
+    max_payload_size: u32
+}
+
+impl FramedReader {
+    fn new(max_payload_size: u32) -> FramedReader {
+        FramedReader { rec_buf: Vec::new(), expect: FRAME_HEADER_SIZE, message_id: None, max_payload_size: max_payload_size }
+    }
+
+    fn feed(&mut self, bytes: &[u8]) {
+        self.rec_buf.extend_from_slice(bytes);
+    }
+
+    fn try_take(&mut self) -> Result<Option<(u16, Vec<u8>)>, DecodeError> {
+        if self.rec_buf.len() < self.expect {
+            return Ok(None);
+        }
+        match self.message_id {
+            None => {
+                let header: Vec<u8> = self.rec_buf.drain(..FRAME_HEADER_SIZE).collect();
+                let id = BigEndian::read_u16(&header[0..2]);
+                let len = BigEndian::read_u32(&header[2..6]);
+                if len > self.max_payload_size {
+                    return Err(DecodeError::PayloadTooLarge { id: id, len: len, max: self.max_payload_size });
+                }
+                self.message_id = Some(id);
+                self.expect = len as usize;
+                self.try_take()
+            }
+            Some(id) => {
+                let payload: Vec<u8> = self.rec_buf.drain(..self.expect).collect();
+                self.message_id = None;
+                self.expect = FRAME_HEADER_SIZE;
+                Ok(Some((id, payload)))
+            }
+        }
+    }
+
+    /// If the connection ends mid-frame, turns whatever was buffered into a
+    /// `TruncatedPayload` error describing what was missing.
+    fn truncated(&self) -> Option<DecodeError> {
+        match self.message_id {
+            Some(id) => Some(DecodeError::TruncatedPayload { id: id, expected: self.expect as u32, got: self.rec_buf.len() }),
+            None if !self.rec_buf.is_empty() => {
+                Some(DecodeError::TruncatedPayload { id: 0, expected: FRAME_HEADER_SIZE as u32, got: self.rec_buf.len() })
+            }
+            None => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(id: u16, payload: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![];
+        bytes.write_u16::<BigEndian>(id).unwrap();
+        bytes.write_u32::<BigEndian>(payload.len() as u32).unwrap();
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    #[test]
+    fn try_take_returns_none_until_a_full_frame_has_arrived() {
+        let mut reader = FramedReader::new(DEFAULT_MAX_FRAME_PAYLOAD_SIZE);
+        let whole = frame(7, b"hello");
+        // A partial header isn't enough.
+        reader.feed(&whole[..3]);
+        assert!(reader.try_take().unwrap().is_none());
+        // Completing the header but not the payload still isn't enough.
+        reader.feed(&whole[3..FRAME_HEADER_SIZE + 2]);
+        assert!(reader.try_take().unwrap().is_none());
+        // The rest of the payload completes the frame.
+        reader.feed(&whole[FRAME_HEADER_SIZE + 2..]);
+        assert_eq!(reader.try_take().unwrap(), Some((7, b"hello".to_vec())));
+    }
+
+    #[test]
+    fn try_take_handles_a_zero_length_payload() {
+        let mut reader = FramedReader::new(DEFAULT_MAX_FRAME_PAYLOAD_SIZE);
+        reader.feed(&frame(3, b""));
+        assert_eq!(reader.try_take().unwrap(), Some((3, vec![])));
+    }
+
+    #[test]
+    fn try_take_yields_several_whole_frames_fed_at_once() {
+        let mut reader = FramedReader::new(DEFAULT_MAX_FRAME_PAYLOAD_SIZE);
+        let mut both = frame(1, b"a");
+        both.extend(frame(2, b"bb"));
+        reader.feed(&both);
+        assert_eq!(reader.try_take().unwrap(), Some((1, b"a".to_vec())));
+        assert_eq!(reader.try_take().unwrap(), Some((2, b"bb".to_vec())));
+        assert_eq!(reader.try_take().unwrap(), None);
+    }
+
+    #[test]
+    fn try_take_rejects_a_payload_length_over_the_cap() {
+        let mut reader = FramedReader::new(4);
+        reader.feed(&frame(9, b"too big"));
+        match reader.try_take() {
+            Err(DecodeError::PayloadTooLarge { id: 9, len: 7, max: 4 }) => {}
+            other => panic!("expected PayloadTooLarge, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn truncated_is_none_on_a_fresh_or_frame_aligned_reader() {
+        let mut reader = FramedReader::new(DEFAULT_MAX_FRAME_PAYLOAD_SIZE);
+        assert!(reader.truncated().is_none());
+        reader.feed(&frame(1, b"ok"));
+        reader.try_take().unwrap();
+        assert!(reader.truncated().is_none());
+    }
+
+    #[test]
+    fn truncated_reports_a_partial_header() {
+        let mut reader = FramedReader::new(DEFAULT_MAX_FRAME_PAYLOAD_SIZE);
+        reader.feed(&frame(1, b"ok")[..3]);
+        match reader.truncated() {
+            Some(DecodeError::TruncatedPayload { id: 0, expected, got: 3 }) if expected == FRAME_HEADER_SIZE as u32 => {}
+            other => panic!("expected a partial-header TruncatedPayload, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn truncated_reports_a_partial_payload() {
+        let mut reader = FramedReader::new(DEFAULT_MAX_FRAME_PAYLOAD_SIZE);
+        reader.feed(&frame(1, b"hello")[..FRAME_HEADER_SIZE + 2]);
+        assert!(reader.try_take().unwrap().is_none());
+        match reader.truncated() {
+            Some(DecodeError::TruncatedPayload { id: 1, expected: 5, got: 2 }) => {}
+            other => panic!("expected a partial-payload TruncatedPayload, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn decode_message_rejects_an_unknown_type() {
+        match decode_message(255, &[]) {
+            Err(DecodeError::UnknownMessageType(255)) => {}
+            other => panic!("expected UnknownMessageType, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn decode_message_parses_a_known_type() {
+        match decode_message(3, &[]) {
+            Ok(ServerMessage::Ping(_)) => {}
+            other => panic!("expected Ping, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn decode_message_passes_udp_tunnel_through_raw() {
+        match decode_message(1, &[1, 2, 3]) {
+            Ok(ServerMessage::UDPTunnel(payload)) => assert_eq!(payload, vec![1, 2, 3]),
+            other => panic!("expected UDPTunnel, got {:?}", other)
+        }
+    }
+}
+
+/// Commands sent from `Client` handles into the event loop thread that actually owns the
+/// socket. Keeping this queue cheap to push onto is the point: `send_message` no longer
+/// blocks on a write, it just takes a brief lock on the sender and enqueues.
+enum EventLoopCommand {
+    Send(Vec<u8>)
+}
+
+/// Pairs the `Send` command queue with the `SetReadiness` half of the event loop's wake
+/// registration, so enqueuing a command also wakes the loop immediately instead of waiting
+/// for it to next poll a socket event.
+struct CommandChannel {
+    cmd_tx: mpsc::Sender<EventLoopCommand>,
+    wake: SetReadiness
+}
+
+impl CommandChannel {
+    fn send(&self, packet: Vec<u8>) -> Result<(), SendError> {
+        match self.cmd_tx.send(EventLoopCommand::Send(packet)) {
+            Ok(()) => {
+                let _ = self.wake.set_readiness(Ready::readable());
+                Ok(())
+            }
+            Err(_) => Err(SendError::EventLoopGone)
+        }
+    }
+}
+
+impl Drop for CommandChannel {
+    fn drop(&mut self) {
+        // Dropping `cmd_tx` is what lets `run_event_loop` notice it should shut down (via
+        // `cmd_rx.try_recv()` returning `Disconnected`), but now that `poll` blocks
+        // indefinitely, the loop also needs one last wake-up to actually notice - otherwise
+        // a loop with no socket events left to wait on would block forever. The real sender
+        // is swapped out and dropped first, so the loop sees `Disconnected` when it wakes.
+        let (dead_tx, _) = mpsc::channel();
+        drop(std::mem::replace(&mut self.cmd_tx, dead_tx));
+        let _ = self.wake.set_readiness(Ready::readable());
+    }
+}
+
+fn would_block(err: &openssl::ssl::Error) -> bool {
+    match *err {
+        openssl::ssl::Error::WantRead(_) | openssl::ssl::Error::WantWrite(_) => true,
+        _ => false
+    }
+}
+
+/// Whether a failed handshake's underlying OpenSSL error stack was a rejected server
+/// certificate, as opposed to some other handshake failure (bad cipher negotiation, reset
+/// connection, etc). OpenSSL reports certificate verification failures through the normal
+/// error queue rather than as their own `openssl::ssl::Error` variant, so this is the only
+/// way to tell them apart.
+fn is_cert_verification_failure(err: &openssl::ssl::Error) -> bool {
+    match *err {
+        openssl::ssl::Error::Ssl(ref stack) => {
+            stack.errors().iter().any(|e| e.reason() == Some("certificate verify failed"))
+        }
+        _ => false
+    }
+}
+
+/// Runs the single-threaded, non-blocking event loop for one control channel.
+///
+/// This owns the `SslStream` outright (no `Mutex`, no contention with callers or the ping
+/// thread) and multiplexes it with mio: reads are fed straight into a `FramedReader` and
+/// dispatched as `Result<ServerMessage, DecodeError>`s, writes are drained from
+/// `send_queue` whenever the socket reports writable, and write interest is only
+/// registered while the queue is non-empty. `wake` is the receiving half of the
+/// `Registration`/`SetReadiness` pair `CommandChannel::send` signals, so `poll` can block
+/// indefinitely and still notice a freshly enqueued command right away instead of only on
+/// the next socket event. Returns (by breaking out) on EOF or a hard I/O error, which is
+/// the one place auto-reconnect needs to hook in.
+fn run_event_loop(
+    mut control_channel: SslStream<TcpStream>,
+    cmd_rx: mpsc::Receiver<EventLoopCommand>,
+    message_tx: mpsc::Sender<Result<ServerMessage, DecodeError>>,
+    max_frame_payload_size: u32,
+    wake: Registration,
+    wake_readiness: SetReadiness
+) {
+    let poll = Poll::new().expect("failed to create mio Poll");
+    let fd = control_channel.get_ref().as_raw_fd();
+    poll.register(&EventedFd(&fd), CONTROL_CHANNEL_TOKEN, Ready::readable(), PollOpt::edge())
+        .expect("failed to register control channel with mio");
+    poll.register(&wake, WAKE_TOKEN, Ready::readable(), PollOpt::edge())
+        .expect("failed to register wake handle with mio");
+
+    let mut send_queue: VecDeque<Cursor<Vec<u8>>> = VecDeque::new();
+    let mut write_interest = false;
+    let mut reader = FramedReader::new(max_frame_payload_size);
+    let mut events = Events::with_capacity(128);
+
+    'event_loop: loop {
+        // Clear the wake flag before draining, not after: a command enqueued (and a wake
+        // signalled) between the clear and the next `poll` still produces an edge
+        // transition `poll` will catch, but one cleared after draining could erase a
+        // signal for a command the drain below already missed.
+        let _ = wake_readiness.set_readiness(Ready::empty());
+
+        loop {
+            match cmd_rx.try_recv() {
+                Ok(EventLoopCommand::Send(packet)) => send_queue.push_back(Cursor::new(packet)),
+                Err(mpsc::TryRecvError::Empty) => break,
+                Err(mpsc::TryRecvError::Disconnected) => break 'event_loop
+            }
+        }
+
+        if !send_queue.is_empty() && !write_interest {
+            poll.reregister(&EventedFd(&fd), CONTROL_CHANNEL_TOKEN, Ready::readable() | Ready::writable(), PollOpt::edge())
+                .expect("failed to reregister control channel with mio");
+            write_interest = true;
+        }
+
+        let poll_result = poll.poll(&mut events, None);
+        if poll_result.is_err() {
+            break 'event_loop;
+        }
+
+        for event in events.iter() {
+            if event.token() != CONTROL_CHANNEL_TOKEN {
+                // The wake token's only job is making `poll` return promptly; the command
+                // drain at the top of the loop already picks up whatever it woke us for.
+                continue;
+            }
+
+            if event.readiness().is_readable() {
+                let mut buf = [0u8; RECV_CHUNK_SIZE];
+                loop {
+                    match control_channel.ssl_read(&mut buf) {
+                        Ok(0) => {
+                            if let Some(err) = reader.truncated() {
+                                let _ = message_tx.send(Err(err));
+                            }
+                            break 'event_loop; // EOF - TODO: drive auto-reconnect from here
+                        }
+                        Ok(n) => {
+                            reader.feed(&buf[..n]);
+                            loop {
+                                match reader.try_take() {
+                                    Ok(Some((id, payload))) => {
+                                        if message_tx.send(decode_message(id, &payload)).is_err() {
+                                            break 'event_loop;
+                                        }
+                                    }
+                                    Ok(None) => break,
+                                    Err(err) => {
+                                        // The length prefix can no longer be trusted, so the
+                                        // framing itself is lost; report it and give up.
+                                        let _ = message_tx.send(Err(err));
+                                        break 'event_loop;
+                                    }
+                                }
+                            }
+                        }
+                        Err(ref err) if would_block(err) => break,
+                        Err(_) => break 'event_loop // TODO: drive auto-reconnect from here
+                    }
+                }
+            }
+
+            if event.readiness().is_writable() {
+                while let Some(mut cursor) = send_queue.pop_front() {
+                    let written_so_far = cursor.position() as usize;
+                    let write_result = control_channel.ssl_write(&cursor.get_ref()[written_so_far..]);
+                    match write_result {
+                        Ok(written) => {
+                            cursor.set_position((written_so_far + written) as u64);
+                            if (cursor.position() as usize) < cursor.get_ref().len() {
+                                send_queue.push_front(cursor);
+                                break;
+                            }
+                        }
+                        Err(ref err) if would_block(err) => {
+                            send_queue.push_front(cursor);
+                            break;
+                        }
+                        Err(_) => break 'event_loop // TODO: drive auto-reconnect from here
+                    }
+                }
+                if send_queue.is_empty() && write_interest {
+                    poll.reregister(&EventedFd(&fd), CONTROL_CHANNEL_TOKEN, Ready::readable(), PollOpt::edge())
+                        .expect("failed to reregister control channel with mio");
+                    write_interest = false;
+                }
+            }
+        }
+    }
+}
 
 pub struct Client {
-    control_channel: Mutex<SslStream<TcpStream>>
+    // `CommandChannel` wraps an `mpsc::Sender`, which is `Send` but not `Sync`, so this needs
+    // a `Mutex` around it (rather than being a bare field) for `Client` itself to stay `Sync`
+    // - the ping thread below holds a `Weak<Client>`, and `Weak<T>: Send` requires
+    // `T: Send + Sync`.
+    cmd_tx: Mutex<CommandChannel>,
+    config: ClientConfig,
+    tls_state: Mutex<TlsState>
 }
 
 // TODO: auto reconnect on ZeroReturnError
 // for that, perhaps a different impl?
 impl Client {
-    pub fn new(host: IpAddr, port: u16, username: &str, password: &str) -> Result<Arc<Client>, Error> {
-        let control_channel = try!(Client::connect(host, port));
-        let client = Arc::new(Client { control_channel: Mutex::new(control_channel) });
+    pub fn new(
+        host: IpAddr,
+        port: u16,
+        username: &str,
+        password: &str,
+        config: ClientConfig
+    ) -> Result<(Arc<Client>, mpsc::Receiver<Result<ServerMessage, DecodeError>>), Error> {
+        let (control_channel, handshake_state) = try!(Client::connect(host, port, &config, None));
+        let session = control_channel.ssl().session().map(|s| s.to_owned());
+        let (cmd_tx, message_rx) = Client::spawn_event_loop(control_channel, config.max_frame_payload_size);
+        let mut sessions = SessionCache::new();
+        if let Some(session) = session {
+            sessions.push(session);
+        }
+        let client = Arc::new(Client {
+            cmd_tx: Mutex::new(cmd_tx),
+            config: config,
+            tls_state: Mutex::new(TlsState { sessions: sessions, last_handshake: handshake_state })
+        });
         try!(client.version_exchange());
         try!(client.authenticate(username, password));
         let ping_client = Arc::downgrade(&client.clone());
@@ -79,35 +872,153 @@ impl Client {
                 let _ = client.ping();
             }
         });
-        Ok(client)
+        Ok((client, message_rx))
+    }
+
+    /// Puts the control channel in non-blocking mode and hands it off to its own event
+    /// loop thread, returning the command sender callers use to enqueue outgoing packets
+    /// and the receiver the caller reads decoded messages from.
+    fn spawn_event_loop(
+        control_channel: SslStream<TcpStream>,
+        max_frame_payload_size: u32
+    ) -> (CommandChannel, mpsc::Receiver<Result<ServerMessage, DecodeError>>) {
+        control_channel.get_ref().set_nonblocking(true).expect("failed to set control channel non-blocking");
+        let (cmd_tx, cmd_rx) = mpsc::channel();
+        let (message_tx, message_rx) = mpsc::channel();
+        let (registration, wake_readiness) = Registration::new2();
+        let loop_wake_readiness = wake_readiness.clone();
+        thread::spawn(move || run_event_loop(control_channel, cmd_rx, message_tx, max_frame_payload_size, registration, loop_wake_readiness));
+        (CommandChannel { cmd_tx: cmd_tx, wake: wake_readiness }, message_rx)
     }
 
-    pub fn reconnect(&mut self, host: IpAddr, port: u16, username: &str, password: &str) -> Result<(), Error> {
-        let control_channel = try!(Client::connect(host, port));
-        self.control_channel = Mutex::new(control_channel);
+    /// Returns whether the most recent handshake (from `new` or `reconnect`) resumed a
+    /// cached session or performed a full negotiation.
+    pub fn handshake_state(&self) -> HandshakeState {
+        self.tls_state.lock().unwrap().last_handshake
+    }
+
+    pub fn reconnect(
+        &mut self,
+        host: IpAddr,
+        port: u16,
+        username: &str,
+        password: &str
+    ) -> Result<mpsc::Receiver<Result<ServerMessage, DecodeError>>, Error> {
+        let had_candidate;
+        let connect_result = {
+            let tls_state = self.tls_state.lock().unwrap();
+            let candidate = tls_state.sessions.most_recent();
+            had_candidate = candidate.is_some();
+            Client::connect(host, port, &self.config, candidate)
+        };
+        let (control_channel, handshake_state) = try!(connect_result);
+        {
+            let mut tls_state = self.tls_state.lock().unwrap();
+            tls_state.last_handshake = handshake_state;
+            if had_candidate && handshake_state == HandshakeState::Full {
+                // The server didn't resume the session we offered; it's no longer valid.
+                tls_state.sessions.evict_most_recent();
+            }
+        }
+        let session = control_channel.ssl().session().map(|s| s.to_owned());
+        let (cmd_tx, message_rx) = Client::spawn_event_loop(control_channel, self.config.max_frame_payload_size);
+        self.cmd_tx = Mutex::new(cmd_tx);
+        if let Some(session) = session {
+            self.tls_state.lock().unwrap().sessions.push(session);
+        }
         try!(self.version_exchange());
         try!(self.authenticate(username, password));
-        Ok(())
+        Ok(message_rx)
     }
 
-    fn connect(host: IpAddr, port: u16) -> Result<SslStream<TcpStream>, ConnectionError> {
+    fn connect(host: IpAddr, port: u16, config: &ClientConfig, session: Option<&SslSession>) -> Result<(SslStream<TcpStream>, HandshakeState), ConnectionError> {
         let mut context: SslContext;
         match SslContext::new(SslMethod::Tlsv1) {
             Ok(val) => context = val,
             Err(err) => return Err(ConnectionError::Ssl(openssl::ssl::Error::from(err)))
         }
-        // TODO: This will do no cert verification. We should have an option for this.
-        context.set_verify(openssl::ssl::SSL_VERIFY_NONE);
-        //context.set_verify(openssl::ssl::SSL_VERIFY_PEER);
+        match config.verify_mode {
+            VerifyMode::None => context.set_verify(SSL_VERIFY_NONE),
+            VerifyMode::SystemRoots => {
+                context.set_verify(SSL_VERIFY_PEER);
+                if let Err(err) = context.set_default_verify_paths() {
+                    return Err(ConnectionError::Ssl(openssl::ssl::Error::from(err)));
+                }
+            }
+            VerifyMode::PinnedCert(ref path) => {
+                context.set_verify(SSL_VERIFY_PEER);
+                if let Err(err) = context.set_CA_file(path) {
+                    return Err(ConnectionError::Ssl(openssl::ssl::Error::from(err)));
+                }
+            }
+        }
+        if let Some(ref client_cert) = config.client_cert {
+            try!(Client::set_client_cert(&mut context, client_cert));
+        }
+        let ssl = match Ssl::new(&context) {
+            Ok(val) => val,
+            Err(err) => return Err(ConnectionError::Ssl(openssl::ssl::Error::from(err)))
+        };
+        let attempting_resume = session.is_some();
+        if let Some(session) = session {
+            if let Err(err) = ssl.set_session(session) {
+                return Err(ConnectionError::Ssl(openssl::ssl::Error::from(err)));
+            }
+        }
         let stream: TcpStream;
         match TcpStream::connect((host, port)) {
             Ok(val) => stream = val,
             Err(err) => return Err(ConnectionError::TcpStream(err))
         }
-        match SslStream::connect(&context, stream) {
+        let control_channel = try!(Client::handshake(ssl, stream));
+        let handshake_state = if attempting_resume && control_channel.ssl().session_reused() {
+            HandshakeState::Resumed
+        } else {
+            HandshakeState::Full
+        };
+        Ok((control_channel, handshake_state))
+    }
+
+    /// Loads a client certificate and private key into `context`, from separate PEM files
+    /// or a single PKCS#12 bundle depending on which `ClientCert` variant was given.
+    fn set_client_cert(context: &mut SslContext, client_cert: &ClientCert) -> Result<(), ConnectionError> {
+        match *client_cert {
+            ClientCert::Pem { ref cert_file, ref key_file } => {
+                if let Err(err) = context.set_certificate_file(cert_file, X509FileType::PEM) {
+                    return Err(ConnectionError::Ssl(openssl::ssl::Error::from(err)));
+                }
+                if let Err(err) = context.set_private_key_file(key_file, X509FileType::PEM) {
+                    return Err(ConnectionError::Ssl(openssl::ssl::Error::from(err)));
+                }
+            }
+            ClientCert::Pkcs12 { ref file, ref password } => {
+                let der = match std::fs::File::open(file).and_then(|mut f| {
+                    let mut buf = Vec::new();
+                    std::io::Read::read_to_end(&mut f, &mut buf).map(|_| buf)
+                }) {
+                    Ok(val) => val,
+                    Err(err) => return Err(ConnectionError::ClientCert(err))
+                };
+                let parsed = match Pkcs12::from_der(&der).and_then(|bundle| bundle.parse(password)) {
+                    Ok(val) => val,
+                    Err(err) => return Err(ConnectionError::Ssl(openssl::ssl::Error::from(err)))
+                };
+                if let Err(err) = context.set_certificate(&parsed.cert) {
+                    return Err(ConnectionError::Ssl(openssl::ssl::Error::from(err)));
+                }
+                if let Err(err) = context.set_private_key(&parsed.pkey) {
+                    return Err(ConnectionError::Ssl(openssl::ssl::Error::from(err)));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn handshake(ssl: Ssl, stream: TcpStream) -> Result<SslStream<TcpStream>, ConnectionError> {
+        match SslStream::connect(ssl, stream) {
             Ok(val) => Ok(val),
             Err(err) => match err {
-                HandshakeError::Failure(handshake_err) => Err(ConnectionError::Ssl(handshake_err)),
+                HandshakeError::Failure(handshake_err) => Err(Client::handshake_failure_error(handshake_err)),
                 HandshakeError::Interrupted(interrupted_stream) => {
                     let mut ssl_stream = interrupted_stream;
                     let mut tries: u8 = 1;
@@ -115,7 +1026,7 @@ impl Client {
                         match ssl_stream.handshake() {
                             Ok(val) => return Ok(val),
                             Err(err) => match err {
-                                HandshakeError::Failure(handshake_err) => return Err(ConnectionError::Ssl(handshake_err)),
+                                HandshakeError::Failure(handshake_err) => return Err(Client::handshake_failure_error(handshake_err)),
                                 HandshakeError::Interrupted(new_interrupted_stream) => {
                                     ssl_stream = new_interrupted_stream;
                                     tries += 1;
@@ -130,6 +1041,18 @@ impl Client {
         }
     }
 
+    /// A handshake failure is reported as `CertVerification` rather than the generic `Ssl`
+    /// variant when the underlying OpenSSL error was the server's certificate failing
+    /// verification, so callers using `VerifyMode::SystemRoots`/`PinnedCert` can tell a
+    /// rejected certificate apart from an unrelated handshake failure.
+    fn handshake_failure_error(err: openssl::ssl::Error) -> ConnectionError {
+        if is_cert_verification_failure(&err) {
+            ConnectionError::CertVerification(err)
+        } else {
+            ConnectionError::Ssl(err)
+        }
+    }
+
     fn version_exchange(&self) -> Result<(), SendError> {
         let major = (VERSION_MAJOR as u32) << 16;
         let minor = (VERSION_MINOR as u32) << 8;
@@ -159,28 +1082,21 @@ impl Client {
         self.send_message(3, ping_message)
     }
 
-    // TODO: error handling
     fn send_message<M: protobuf::core::Message>(&self, id: u16, message: M) -> Result<(), SendError> {
         let mut packet = vec![];
         // ID - what type of message are we sending
         packet.write_u16::<BigEndian>(id).unwrap();
-        let payload = message.write_to_bytes().unwrap();
-        if payload.len() as u64 > u32::max_value() as u64  {
+        let payload = try!(message.write_to_bytes());
+        if payload.len() as u64 > u32::max_value() as u64 {
             // We can't send a message with a payload bigger than this
-            // TODO: figure out what to do here
-            panic!();
+            return Err(SendError::MessageTooLarge("outgoing message payload exceeds u32::max_value() bytes"));
         }
         // The length of the payload
         packet.write_u32::<BigEndian>(payload.len() as u32).unwrap();
         // The payload itself
         packet.extend(payload);
         // Panic on poisoned mutex - this is desired.
-        // https://doc.rust-lang.org/std/sync/struct.Mutex.html#poisoning
-        let mut channel = self.control_channel.lock().unwrap();
-        match channel.ssl_write(&*packet) {
-            Err(err) => Err(SendError::Ssl(err)),
-            Ok(_) => Ok(())
-        }
+        self.cmd_tx.lock().unwrap().send(packet)
     }
 }
 